@@ -0,0 +1,151 @@
+//! IR nodes for the threads proposal's atomic instructions.
+//!
+//! Added alongside the bulk-memory nodes in [`crate::ir::bulk_memory`] since
+//! [`crate::passes::thread_xform`] needs both to build its once-only,
+//! cross-thread memory initializer.
+
+use crate::emit::{Emit, EmitContext};
+use crate::ir::Instr;
+use crate::MemoryId;
+
+/// The read-modify-write operation an [`AtomicRmw`] performs.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum AtomicOp {
+    Add,
+    Sub,
+    And,
+    Or,
+    Xor,
+    Xchg,
+    Cmpxchg,
+}
+
+/// The bit width an atomic instruction operates on.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum AtomicWidth {
+    I32,
+    I64,
+    I32_8,
+    I32_16,
+    I64_8,
+    I64_16,
+    I64_32,
+}
+
+impl AtomicWidth {
+    fn index(&self) -> u8 {
+        match self {
+            AtomicWidth::I32 => 0,
+            AtomicWidth::I64 => 1,
+            AtomicWidth::I32_8 => 2,
+            AtomicWidth::I32_16 => 3,
+            AtomicWidth::I64_8 => 4,
+            AtomicWidth::I64_16 => 5,
+            AtomicWidth::I64_32 => 6,
+        }
+    }
+}
+
+/// The alignment/offset pair every atomic memory instruction carries.
+#[derive(Clone, Copy, Debug)]
+pub struct MemArg {
+    /// The expected alignment in bytes, a power of two; it is encoded as its
+    /// base-2 logarithm.
+    pub align: u32,
+    /// The static offset added to the dynamic address operand.
+    pub offset: u32,
+}
+
+/// `{i32,i64}.atomic.rmw{8,16,32}.{add,sub,and,or,xor,xchg,cmpxchg}[_u]`: an
+/// atomic read-modify-write. `Cmpxchg` reads two extra operands off the
+/// stack (the expected value and the replacement) rather than one.
+#[derive(Clone, Debug)]
+pub struct AtomicRmw {
+    /// The memory the address operand indexes into.
+    pub memory: MemoryId,
+    /// Which read-modify-write operation to perform.
+    pub op: AtomicOp,
+    /// The bit width to operate on.
+    pub width: AtomicWidth,
+    /// This instruction's alignment/offset immediate.
+    pub arg: MemArg,
+}
+
+impl From<AtomicRmw> for Instr {
+    fn from(x: AtomicRmw) -> Instr {
+        Instr::AtomicRmw(x)
+    }
+}
+
+impl Emit for AtomicRmw {
+    fn emit(&self, cx: &mut EmitContext) {
+        const OPS: [AtomicOp; 7] = [
+            AtomicOp::Add,
+            AtomicOp::Sub,
+            AtomicOp::And,
+            AtomicOp::Or,
+            AtomicOp::Xor,
+            AtomicOp::Xchg,
+            AtomicOp::Cmpxchg,
+        ];
+        let op_index = OPS.iter().position(|op| *op == self.op).unwrap() as u8;
+        let opcode = 0x1e + op_index * 7 + self.width.index();
+
+        cx.encoder.byte(0xfe);
+        cx.encoder.byte(opcode);
+        cx.encoder.u32(self.arg.align.trailing_zeros());
+        cx.encoder.u32(self.arg.offset);
+    }
+}
+
+/// `memory.atomic.notify`: wake up to some number of agents waiting on an
+/// address.
+#[derive(Clone, Debug)]
+pub struct AtomicNotify {
+    /// The memory the address operand indexes into.
+    pub memory: MemoryId,
+    /// This instruction's alignment/offset immediate.
+    pub arg: MemArg,
+}
+
+impl From<AtomicNotify> for Instr {
+    fn from(x: AtomicNotify) -> Instr {
+        Instr::AtomicNotify(x)
+    }
+}
+
+impl Emit for AtomicNotify {
+    fn emit(&self, cx: &mut EmitContext) {
+        cx.encoder.byte(0xfe);
+        cx.encoder.byte(0x00);
+        cx.encoder.u32(self.arg.align.trailing_zeros());
+        cx.encoder.u32(self.arg.offset);
+    }
+}
+
+/// `memory.atomic.wait32`/`memory.atomic.wait64`: block until notified, a
+/// timeout expires, or the expected value no longer matches.
+#[derive(Clone, Debug)]
+pub struct AtomicWait {
+    /// The memory the address operand indexes into.
+    pub memory: MemoryId,
+    /// Whether this is a 64-bit (`true`) or 32-bit (`false`) wait.
+    pub sixty_four: bool,
+    /// This instruction's alignment/offset immediate.
+    pub arg: MemArg,
+}
+
+impl From<AtomicWait> for Instr {
+    fn from(x: AtomicWait) -> Instr {
+        Instr::AtomicWait(x)
+    }
+}
+
+impl Emit for AtomicWait {
+    fn emit(&self, cx: &mut EmitContext) {
+        cx.encoder.byte(0xfe);
+        cx.encoder.byte(if self.sixty_four { 0x02 } else { 0x01 });
+        cx.encoder.u32(self.arg.align.trailing_zeros());
+        cx.encoder.u32(self.arg.offset);
+    }
+}
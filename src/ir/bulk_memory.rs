@@ -0,0 +1,101 @@
+//! IR nodes for the bulk-memory proposal's instructions.
+//!
+//! These slot into the rest of the instruction list alongside the other
+//! `*.rs` submodules of `ir`; they're broken out on their own here because
+//! they all share the same handful of operands (a [`MemoryId`] and,
+//! sometimes, a [`DataId`]).
+
+use crate::emit::{Emit, EmitContext};
+use crate::ir::Instr;
+use crate::{DataId, MemoryId};
+
+/// `memory.init`: copy a range of a passive data segment into a memory.
+#[derive(Clone, Debug)]
+pub struct MemoryInit {
+    /// The memory to copy into.
+    pub memory: MemoryId,
+    /// The passive data segment to copy from.
+    pub data: DataId,
+}
+
+impl From<MemoryInit> for Instr {
+    fn from(x: MemoryInit) -> Instr {
+        Instr::MemoryInit(x)
+    }
+}
+
+impl Emit for MemoryInit {
+    fn emit(&self, cx: &mut EmitContext) {
+        cx.encoder.byte(0xfc);
+        cx.encoder.byte(0x08);
+        cx.encoder.u32(cx.indices.get_data_index(self.data));
+        cx.encoder.u32(cx.indices.get_memory_index(self.memory));
+    }
+}
+
+/// `data.drop`: discard a passive data segment, freeing the engine to
+/// release its bytes. Subsequent `memory.init`s of the same segment trap.
+#[derive(Clone, Debug)]
+pub struct DataDrop {
+    /// The passive data segment to drop.
+    pub data: DataId,
+}
+
+impl From<DataDrop> for Instr {
+    fn from(x: DataDrop) -> Instr {
+        Instr::DataDrop(x)
+    }
+}
+
+impl Emit for DataDrop {
+    fn emit(&self, cx: &mut EmitContext) {
+        cx.encoder.byte(0xfc);
+        cx.encoder.byte(0x09);
+        cx.encoder.u32(cx.indices.get_data_index(self.data));
+    }
+}
+
+/// `memory.copy`: copy a range of memory to another (possibly overlapping)
+/// range within the same memory.
+#[derive(Clone, Debug)]
+pub struct MemoryCopy {
+    /// The memory to copy within.
+    pub memory: MemoryId,
+}
+
+impl From<MemoryCopy> for Instr {
+    fn from(x: MemoryCopy) -> Instr {
+        Instr::MemoryCopy(x)
+    }
+}
+
+impl Emit for MemoryCopy {
+    fn emit(&self, cx: &mut EmitContext) {
+        cx.encoder.byte(0xfc);
+        cx.encoder.byte(0x0a);
+        let idx = cx.indices.get_memory_index(self.memory);
+        cx.encoder.u32(idx);
+        cx.encoder.u32(idx);
+    }
+}
+
+/// `memory.fill`: set a range of memory to a repeated byte value.
+#[derive(Clone, Debug)]
+pub struct MemoryFill {
+    /// The memory to fill.
+    pub memory: MemoryId,
+}
+
+impl From<MemoryFill> for Instr {
+    fn from(x: MemoryFill) -> Instr {
+        Instr::MemoryFill(x)
+    }
+}
+
+impl Emit for MemoryFill {
+    fn emit(&self, cx: &mut EmitContext) {
+        cx.encoder.byte(0xfc);
+        cx.encoder.byte(0x0b);
+        cx.encoder.u32(cx.indices.get_memory_index(self.memory));
+    }
+}
@@ -0,0 +1,7 @@
+//! The instructions that make up a wasm function body.
+
+mod atomics;
+mod bulk_memory;
+
+pub use self::atomics::{AtomicNotify, AtomicOp, AtomicRmw, AtomicWait, AtomicWidth, MemArg};
+pub use self::bulk_memory::{DataDrop, MemoryCopy, MemoryFill, MemoryInit};
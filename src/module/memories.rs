@@ -1,11 +1,53 @@
 //! Memories used in a wasm module.
 
+use std::collections::BTreeMap;
+
+use failure::bail;
+
 use crate::emit::{Emit, EmitContext, Section};
 use crate::ir::Value;
 use crate::parse::IndicesToIds;
 use crate::tombstone_arena::{Id, Tombstone, TombstoneArena};
 use crate::{GlobalId, ImportId, InitExpr, Module, Result};
 
+/// The id of a passive data segment.
+///
+/// Unlike active segments, passive segments aren't implicitly associated
+/// with any particular `Memory`; they're named from a module-wide namespace
+/// and only take on a destination memory when a `memory.init` instruction
+/// copies them in at run time.
+pub type DataId = Id<PassiveData>;
+
+/// A passive data segment: a blob of bytes with no static or
+/// global-relative offset, instantiated into a memory on demand via
+/// `memory.init` and retired with `data.drop`.
+#[derive(Debug)]
+pub struct PassiveData {
+    id: DataId,
+    /// The raw bytes of this segment.
+    pub value: Vec<u8>,
+}
+
+impl Tombstone for PassiveData {
+    fn on_delete(&mut self) {
+        self.value = Vec::new();
+    }
+}
+
+impl PassiveData {
+    /// Returns the id of this passive data segment.
+    pub fn id(&self) -> DataId {
+        self.id
+    }
+}
+
+impl Emit for PassiveData {
+    fn emit(&self, cx: &mut EmitContext) {
+        cx.encoder.byte(0x01); // flag: passive
+        cx.encoder.bytes(&self.value);
+    }
+}
+
 /// The id of a memory.
 pub type MemoryId = Id<Memory>;
 
@@ -34,8 +76,12 @@ impl Tombstone for Memory {
 
 /// An abstraction for the initialization values of a `Memory`.
 ///
-/// This houses all the data sections of a wasm executable that as associated
-/// with this `Memory`.
+/// This houses all the *active* data segments of a wasm executable that are
+/// associated with this `Memory`, each at either a static offset or an
+/// offset read from a global.
+/// Passive segments have no implicit destination memory, so they live in
+/// [`ModuleMemories`]'s own module-wide [`DataId`] namespace instead; see
+/// [`ModuleMemories::add_passive_data`].
 #[derive(Debug, Default)]
 pub struct MemoryData {
     absolute: Vec<(u32, Vec<u8>)>,
@@ -48,6 +94,11 @@ impl Memory {
         self.id
     }
 
+    /// Returns this memory's static initializer image; see [`MemoryData::flatten`].
+    pub fn data_image(&self) -> Vec<u8> {
+        self.data.flatten().dense()
+    }
+
     pub(crate) fn emit_data(&self) -> impl Iterator<Item = (InitExpr, &[u8])> {
         let absolute = self
             .data
@@ -80,6 +131,7 @@ impl Emit for Memory {
 #[derive(Debug, Default)]
 pub struct ModuleMemories {
     arena: TombstoneArena<Memory>,
+    passive: TombstoneArena<PassiveData>,
 }
 
 impl ModuleMemories {
@@ -147,6 +199,44 @@ impl ModuleMemories {
     pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut Memory> {
         self.arena.iter_mut().map(|(_, f)| f)
     }
+
+    /// Adds a new passive data segment to this module, returning the id used
+    /// to reference it from `memory.init` and `data.drop` instructions.
+    pub fn add_passive_data(&mut self, value: Vec<u8>) -> DataId {
+        let id = self.passive.next_id();
+        let id2 = self.passive.alloc(PassiveData { id, value });
+        debug_assert_eq!(id, id2);
+        id
+    }
+
+    /// Gets a reference to a passive data segment given its id
+    pub fn get_passive_data(&self, id: DataId) -> &PassiveData {
+        &self.passive[id]
+    }
+
+    /// Gets a mutable reference to a passive data segment given its id
+    pub fn get_passive_data_mut(&mut self, id: DataId) -> &mut PassiveData {
+        &mut self.passive[id]
+    }
+
+    /// Removes a passive data segment from this module.
+    ///
+    /// It is up to you to ensure that any potential references to the
+    /// deleted segment are also removed, eg `memory.init` and `data.drop`
+    /// instructions.
+    pub fn delete_passive_data(&mut self, id: DataId) {
+        self.passive.delete(id);
+    }
+
+    /// Get a shared reference to this module's passive data segments.
+    pub fn passive_data(&self) -> impl Iterator<Item = &PassiveData> {
+        self.passive.iter().map(|(_, d)| d)
+    }
+
+    /// Get a mutable reference to this module's passive data segments.
+    pub fn passive_data_mut(&mut self) -> impl Iterator<Item = &mut PassiveData> {
+        self.passive.iter_mut().map(|(_, d)| d)
+    }
 }
 
 impl Module {
@@ -166,6 +256,43 @@ impl Module {
         }
         Ok(())
     }
+
+    /// Parse the data section, ingesting both active segments (with a
+    /// statically known offset into some memory) and passive segments (the
+    /// bulk-memory proposal's flag-prefixed encoding) into the module.
+    pub(crate) fn parse_data(
+        &mut self,
+        section: wasmparser::DataSectionReader,
+        ids: &IndicesToIds,
+    ) -> Result<()> {
+        log::debug!("parse data section");
+        for d in section {
+            let d = d?;
+            match d.kind {
+                wasmparser::DataKind::Passive => {
+                    self.memories.add_passive_data(d.data.to_vec());
+                }
+                wasmparser::DataKind::Active {
+                    memory_index,
+                    init_expr,
+                } => {
+                    let memory = ids.get_memory(memory_index)?;
+                    let offset = InitExpr::eval(&init_expr, ids)?;
+                    let memory = self.memories.get_mut(memory);
+                    match offset {
+                        InitExpr::Value(Value::I32(pos)) => {
+                            memory.data.add_absolute(pos as u32, d.data.to_vec());
+                        }
+                        InitExpr::Global(global) => {
+                            memory.data.add_relative(global, d.data.to_vec());
+                        }
+                        _ => bail!("invalid initializer expression for data segment"),
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
 }
 
 impl Emit for ModuleMemories {
@@ -219,4 +346,250 @@ impl MemoryData {
             .map(move |(id, data)| (InitExpr::Global(id), data));
         absolute.chain(relative)
     }
+
+    /// Flattens the `absolute` segments into one coalesced image, later
+    /// segments winning on overlap. `relative` segments are skipped; see
+    /// [`FlattenedImage::skipped_globals`].
+    pub fn flatten(&self) -> FlattenedImage {
+        let mut bytes: BTreeMap<u32, u8> = BTreeMap::new();
+        for (pos, data) in self.absolute.iter() {
+            for (i, byte) in data.iter().enumerate() {
+                bytes.insert(pos + i as u32, *byte);
+            }
+        }
+
+        let mut coalesced = Vec::new();
+        let mut iter = bytes.into_iter().peekable();
+        while let Some((start, byte)) = iter.next() {
+            let mut run = vec![byte];
+            let mut last = start;
+            while let Some(&(next, _)) = iter.peek() {
+                if next != last + 1 {
+                    break;
+                }
+                let (_, byte) = iter.next().unwrap();
+                run.push(byte);
+                last = next;
+            }
+            coalesced.push((start, run));
+        }
+
+        FlattenedImage {
+            coalesced,
+            skipped_globals: self.globals().collect(),
+        }
+    }
+}
+
+/// The static initializer image reconstructed by [`MemoryData::flatten`].
+#[derive(Debug, Default)]
+pub struct FlattenedImage {
+    coalesced: Vec<(u32, Vec<u8>)>,
+    skipped_globals: Vec<GlobalId>,
+}
+
+impl FlattenedImage {
+    /// Returns the coalesced, sorted runs of initialized bytes.
+    pub fn coalesced(&self) -> &[(u32, Vec<u8>)] {
+        &self.coalesced
+    }
+
+    /// Returns the base globals of `relative` segments that were skipped.
+    pub fn skipped_globals(&self) -> &[GlobalId] {
+        &self.skipped_globals
+    }
+
+    /// Renders this image as one dense buffer starting at offset `0`.
+    pub fn dense(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        for (pos, data) in self.coalesced.iter() {
+            let start = *pos as usize;
+            let end = start + data.len();
+            if buf.len() < end {
+                buf.resize(end, 0);
+            }
+            buf[start..end].copy_from_slice(data);
+        }
+        buf
+    }
+}
+
+#[cfg(test)]
+mod flatten_tests {
+    use super::*;
+
+    #[test]
+    fn overlapping_segments_last_writer_wins() {
+        let mut data = MemoryData::default();
+        data.add_absolute(0, vec![1, 1, 1, 1]);
+        data.add_absolute(2, vec![2, 2]);
+        assert_eq!(data.flatten().dense(), vec![1, 1, 2, 2]);
+    }
+
+    #[test]
+    fn adjacent_segments_coalesce() {
+        let mut data = MemoryData::default();
+        data.add_absolute(4, vec![1, 2]);
+        data.add_absolute(0, vec![9, 9, 9, 9]);
+        assert_eq!(
+            data.flatten().coalesced(),
+            &[(0, vec![9, 9, 9, 9, 1, 2])][..],
+        );
+    }
+
+    #[test]
+    fn gap_between_segments_is_not_coalesced() {
+        let mut data = MemoryData::default();
+        data.add_absolute(0, vec![1]);
+        data.add_absolute(10, vec![2]);
+        assert_eq!(data.flatten().coalesced(), &[(0, vec![1]), (10, vec![2])][..]);
+        assert_eq!(
+            data.flatten().dense(),
+            vec![1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2],
+        );
+    }
+
+    #[test]
+    fn relative_segments_do_not_appear_in_the_image() {
+        let mut data = MemoryData::default();
+        data.add_absolute(0, vec![1]);
+        assert!(data.flatten().skipped_globals().is_empty());
+        assert_eq!(data.globals().count(), 0);
+
+        let global = TombstoneArena::<crate::Global>::default().next_id();
+        data.add_relative(global, vec![2, 2]);
+        assert_eq!(data.flatten().skipped_globals(), &[global][..]);
+        assert_eq!(data.flatten().dense(), vec![1]);
+    }
+}
+
+/// Below this many consecutive zero bytes inside a segment, it's not worth
+/// splitting the segment in two.
+const MIN_ZERO_GAP_TO_SPLIT: usize = 8;
+
+impl ModuleMemories {
+    /// Rewrites every memory's `absolute` data segments to minimize their
+    /// encoded size; see [`MemoryData::optimize`].
+    pub fn optimize_data(&mut self) {
+        for memory in self.iter_mut() {
+            memory.data.optimize();
+        }
+    }
+}
+
+impl MemoryData {
+    /// Coalesces, zero-trims, and gap-splits this data's `absolute`
+    /// segments to minimize their encoded size, without changing the
+    /// memory image they produce. `relative` segments are untouched; this
+    /// is idempotent.
+    pub fn optimize(&mut self) {
+        if self.absolute.is_empty() {
+            return;
+        }
+        self.absolute = self
+            .flatten()
+            .coalesced()
+            .iter()
+            .flat_map(|(pos, data)| split_zero_gaps(*pos, data))
+            .filter_map(trim_zeroes)
+            .collect();
+    }
+}
+
+/// Splits `data` (starting at `pos`) around any internal run of zero bytes
+/// at least [`MIN_ZERO_GAP_TO_SPLIT`] long, so that run doesn't need to be
+/// encoded as part of either half.
+fn split_zero_gaps(pos: u32, data: &[u8]) -> Vec<(u32, Vec<u8>)> {
+    let mut segments = Vec::new();
+    let mut start = 0;
+    let mut i = 0;
+    while i < data.len() {
+        if data[i] != 0 {
+            i += 1;
+            continue;
+        }
+        let gap_start = i;
+        while i < data.len() && data[i] == 0 {
+            i += 1;
+        }
+        if i - gap_start >= MIN_ZERO_GAP_TO_SPLIT {
+            if gap_start > start {
+                segments.push((pos + start as u32, data[start..gap_start].to_vec()));
+            }
+            start = i;
+        }
+    }
+    if start < data.len() {
+        segments.push((pos + start as u32, data[start..].to_vec()));
+    }
+    segments
+}
+
+/// Trims leading and trailing zero bytes from a segment, adjusting its
+/// offset to match. Returns `None` if the segment was entirely zeroes, since
+/// such a segment encodes nothing that instantiation wouldn't already do by
+/// default.
+fn trim_zeroes((pos, data): (u32, Vec<u8>)) -> Option<(u32, Vec<u8>)> {
+    let leading = data.iter().take_while(|b| **b == 0).count();
+    if leading == data.len() {
+        return None;
+    }
+    let trailing = data.iter().rev().take_while(|b| **b == 0).count();
+    let trimmed = data[leading..data.len() - trailing].to_vec();
+    Some((pos + leading as u32, trimmed))
+}
+
+#[cfg(test)]
+mod optimize_tests {
+    use super::*;
+
+    #[test]
+    fn short_gap_is_not_split() {
+        let mut data = MemoryData::default();
+        let mut bytes = vec![1];
+        bytes.extend(std::iter::repeat(0).take(MIN_ZERO_GAP_TO_SPLIT - 1));
+        bytes.push(1);
+        data.add_absolute(0, bytes.clone());
+        data.optimize();
+        assert_eq!(data.absolute, vec![(0, bytes)]);
+    }
+
+    #[test]
+    fn gap_at_threshold_is_split() {
+        let mut data = MemoryData::default();
+        let mut bytes = vec![1];
+        bytes.extend(std::iter::repeat(0).take(MIN_ZERO_GAP_TO_SPLIT));
+        bytes.push(1);
+        let end = bytes.len() as u32 - 1;
+        data.add_absolute(0, bytes);
+        data.optimize();
+        assert_eq!(data.absolute, vec![(0, vec![1]), (end, vec![1])]);
+    }
+
+    #[test]
+    fn leading_and_trailing_zeroes_are_trimmed() {
+        let mut data = MemoryData::default();
+        data.add_absolute(0, vec![0, 0, 1, 2, 0, 0]);
+        data.optimize();
+        assert_eq!(data.absolute, vec![(2, vec![1, 2])]);
+    }
+
+    #[test]
+    fn all_zero_segment_is_dropped() {
+        let mut data = MemoryData::default();
+        data.add_absolute(0, vec![0, 0, 0]);
+        data.optimize();
+        assert!(data.absolute.is_empty());
+    }
+
+    #[test]
+    fn optimizing_twice_is_a_no_op() {
+        let mut data = MemoryData::default();
+        data.add_absolute(0, vec![1, 1, 1, 1]);
+        data.add_absolute(2, vec![2, 2]);
+        data.optimize();
+        let once = data.absolute.clone();
+        data.optimize();
+        assert_eq!(data.absolute, once);
+    }
 }
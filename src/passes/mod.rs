@@ -0,0 +1,3 @@
+//! Optional transformation passes that rewrite a `Module` in place.
+
+pub mod thread_xform;
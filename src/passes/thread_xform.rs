@@ -0,0 +1,282 @@
+//! A pass that enables shared-memory threading for a module.
+//!
+//! This is a port of the transform `wasm-bindgen-threads-xform` applies to
+//! modules produced by toolchains that don't natively emit threaded wasm: it
+//! takes an ordinary, single-threaded module and rewrites it so that many
+//! threads can safely share one `Memory` and run its data segments' once-only
+//! initialization exactly one time, no matter which thread gets there first.
+
+use crate::ir::*;
+use crate::module::memories::MemoryData;
+use crate::{DataId, FunctionBuilder, FunctionId, GlobalId, GlobalKind, InitExpr, MemoryId, Module, Result, Value};
+
+/// The address, in linear memory, of the guard word used to make
+/// `__wasm_init_memory` idempotent across threads. It takes one of three
+/// values: `0` (uninitialized), `1` (another thread is initializing), or `2`
+/// (initialization is done).
+///
+/// [`run`] checks that every thread's stack (which is laid out downward from
+/// the stack pointer's original value, per thread id) stays clear of this
+/// word before rewriting anything; see [`check_guard_is_unclaimed`].
+const INIT_GUARD_OFFSET: i32 = 0;
+
+/// The guard word is a plain `i32`.
+const INIT_GUARD_SIZE: i32 = 4;
+
+/// Configuration for [`run`].
+#[derive(Debug, Clone)]
+pub struct ThreadConfig {
+    /// The memory to share across threads.
+    pub memory: MemoryId,
+    /// How many wasm pages of stack to reserve per thread.
+    pub stack_pages: u32,
+    /// The maximum number of threads that will ever instantiate this module
+    /// concurrently. Used to size the shared memory's required `maximum`.
+    pub max_threads: u32,
+    /// A global, set by the embedder before a thread's entry point runs,
+    /// holding that thread's zero-based id. Thread 0 owns the stack region
+    /// the single-threaded module was already built with.
+    pub thread_id: GlobalId,
+    /// The module's stack pointer global, lowered per-thread so each thread
+    /// gets its own non-overlapping stack.
+    pub stack_pointer: GlobalId,
+}
+
+/// Turn `module` into a thread-safe module sharing `config.memory`.
+///
+/// Concretely, this:
+///
+/// 1. Marks the memory `shared` and grows its `maximum` to fit
+///    `config.max_threads` worth of extra stack space.
+/// 2. Converts the memory's active data segments into a single passive
+///    segment, plus a generated `__wasm_init_memory` function that
+///    `memory.init`s it.
+/// 3. Makes `__wasm_init_memory` safe to call from every thread at once by
+///    having threads race on an atomic compare-exchange over a guard word;
+///    the winner runs the real initialization and notifies the guard, while
+///    everyone else waits on it.
+/// 4. Exports a generated `__wasm_init_tls` function that sets the stack
+///    pointer global to `stack_top - tid * stack_size`; the global's own
+///    initializer is left as it was.
+pub fn run(module: &mut Module, config: &ThreadConfig) -> Result<()> {
+    let stack_top = current_stack_top(module, config)?;
+    check_guard_is_unclaimed(config, stack_top)?;
+
+    share_memory(module, config)?;
+    let (data, image_len) = passivize_data_segments(module, config.memory)?;
+    let init_func = build_init_memory_function(module, config, data, image_len)?;
+    module.exports.add("__wasm_init_memory", init_func);
+    lower_stack_pointer(module, config, stack_top)?;
+    Ok(())
+}
+
+/// Reads the stack pointer global's current constant initializer, which is
+/// thread 0's (and the original single-threaded module's) stack top.
+fn current_stack_top(module: &Module, config: &ThreadConfig) -> Result<i32> {
+    match &module.globals.get(config.stack_pointer).kind {
+        GlobalKind::Local(InitExpr::Value(Value::I32(v))) => Ok(*v),
+        _ => failure::bail!("stack pointer global does not have a constant i32 initializer"),
+    }
+}
+
+/// Every thread's stack occupies `[stack_top - (tid + 1) * stack_size,
+/// stack_top - tid * stack_size)`, so the lowest address any thread's stack
+/// reaches is `stack_top - max_threads * stack_size`. Make sure that stays
+/// above the guard word instead of silently assuming address `0` is free.
+fn check_guard_is_unclaimed(config: &ThreadConfig, stack_top: i32) -> Result<()> {
+    let stack_size = stack_size_bytes(config)?;
+    let max_threads = i32::try_from(config.max_threads)
+        .map_err(|_| failure::format_err!("max_threads is too large"))?;
+    let lowest_stack_addr = stack_top
+        .checked_sub(
+            max_threads
+                .checked_mul(stack_size)
+                .ok_or_else(|| failure::format_err!("max_threads * stack_pages overflows i32"))?,
+        )
+        .ok_or_else(|| failure::format_err!("stack_top - max_threads * stack_size underflows"))?;
+    if lowest_stack_addr < INIT_GUARD_OFFSET + INIT_GUARD_SIZE {
+        failure::bail!(
+            "the lowest thread stack would reach address {}, which collides with the \
+             init guard word reserved at [{}, {}); raise the stack pointer or shrink \
+             max_threads/stack_pages",
+            lowest_stack_addr,
+            INIT_GUARD_OFFSET,
+            INIT_GUARD_OFFSET + INIT_GUARD_SIZE,
+        );
+    }
+    Ok(())
+}
+
+fn stack_size_bytes(config: &ThreadConfig) -> Result<i32> {
+    i32::try_from(config.stack_pages)
+        .ok()
+        .and_then(|pages| pages.checked_mul(65536))
+        .ok_or_else(|| failure::format_err!("stack_pages is too large"))
+}
+
+/// Step 1: mark the memory shared, and size it to fit the original memory
+/// plus every thread's stack.
+fn share_memory(module: &mut Module, config: &ThreadConfig) -> Result<()> {
+    let memory = module.memories.get_mut(config.memory);
+    if memory.shared {
+        failure::bail!(
+            "memory {:?} is already shared; this pass is not idempotent and must only \
+             run once per module",
+            config.memory
+        );
+    }
+    memory.shared = true;
+    memory.maximum = Some(
+        config
+            .max_threads
+            .checked_mul(config.stack_pages)
+            .and_then(|extra| memory.initial.checked_add(extra))
+            .ok_or_else(|| {
+                failure::format_err!(
+                    "initial + max_threads * stack_pages overflows u32"
+                )
+            })?,
+    );
+    Ok(())
+}
+
+/// Step 2: drain `memory`'s active segments and re-insert their bytes, in
+/// order, as a single passive segment. Later segments overwrite earlier ones
+/// on overlap, matching what applying the active segments eagerly at
+/// instantiation time would have produced. Returns the new segment's id
+/// along with the length of the flattened image, since `memory.init` needs
+/// both to copy it back in.
+fn passivize_data_segments(module: &mut Module, memory: MemoryId) -> Result<(DataId, u32)> {
+    let data = std::mem::take(&mut module.memories.get_mut(memory).data);
+    let mut image: Vec<u8> = Vec::new();
+    for (offset, bytes) in MemoryData::into_iter(data) {
+        let offset = match offset {
+            InitExpr::Value(Value::I32(v)) => v as usize,
+            _ => failure::bail!(
+                "cannot passivize a data segment with a global-relative offset"
+            ),
+        };
+        let end = offset + bytes.len();
+        if image.len() < end {
+            image.resize(end, 0);
+        }
+        image[offset..end].copy_from_slice(&bytes);
+    }
+    let len = image.len() as u32;
+    Ok((module.memories.add_passive_data(image), len))
+}
+
+/// Step 3: build `__wasm_init_memory`, guarded by an atomic compare-exchange
+/// on `INIT_GUARD_OFFSET` so only the first thread to reach it actually runs
+/// `memory.init`; every other thread blocks on `memory.atomic.wait32` until
+/// that thread's `atomic.notify` wakes it back up.
+///
+/// `i32.atomic.rmw.cmpxchg` leaves the *old* value of the guard on the
+/// stack, and wasm's `if` branches on that value being non-zero, so the
+/// thread that wins the race (old value `0`) takes the `alternative` arm,
+/// while every later thread (old value non-zero, already claimed) takes the
+/// `consequent` arm and waits.
+///
+/// The winner finishes by swapping the guard to `2` (done) before notifying,
+/// rather than leaving it at `1` (in-progress). That matters for threads that
+/// arrive after initialization has already completed: `memory.atomic.wait32`
+/// atomically compares the guard against its expected value (`1`) at the
+/// moment it's called, and returns immediately without blocking on a
+/// mismatch — so once the guard reads `2`, latecomers fall straight through
+/// instead of waiting on a notify that already fired.
+fn build_init_memory_function(
+    module: &mut Module,
+    config: &ThreadConfig,
+    data: DataId,
+    image_len: u32,
+) -> Result<FunctionId> {
+    let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+    builder.name("__wasm_init_memory".to_string());
+    let memory = config.memory;
+    let image_len = image_len as i32;
+
+    let mut body = builder.func_body();
+    body
+        // Race to claim the guard: `cmpxchg(addr, 0, 1)`. Whoever observes
+        // the old value `0` is the thread responsible for initialization.
+        .i32_const(INIT_GUARD_OFFSET)
+        .i32_const(0)
+        .i32_const(1)
+        .instr(AtomicRmw {
+            memory,
+            op: AtomicOp::Cmpxchg,
+            width: AtomicWidth::I32,
+            arg: MemArg { align: 4, offset: 0 },
+        })
+        .instr(If {
+            consequent: {
+                // Old value was non-zero: someone else already claimed (or
+                // finished) the guard. Wait for it to read `2`; if it
+                // already does, `wait32`'s atomic compare returns at once.
+                let mut loser = body.dangling_instr_seq(None);
+                loser
+                    .i32_const(INIT_GUARD_OFFSET)
+                    .i32_const(1)
+                    .i64_const(-1) // wait forever
+                    .instr(AtomicWait {
+                        memory,
+                        sixty_four: false,
+                        arg: MemArg { align: 4, offset: 0 },
+                    })
+                    .drop();
+                loser.id()
+            },
+            alternative: {
+                // Old value was `0`: we won the race and are responsible
+                // for running the real initialization.
+                let mut winner = body.dangling_instr_seq(None);
+                winner
+                    .i32_const(0) // dest offset in memory
+                    .i32_const(0) // src offset into the passive segment
+                    .i32_const(image_len) // number of bytes to copy
+                    .instr(MemoryInit { memory, data })
+                    .instr(DataDrop { data })
+                    // Mark the guard done (not just claimed), so threads
+                    // that check it after we've already notified don't
+                    // block waiting for a notify that already fired.
+                    .i32_const(INIT_GUARD_OFFSET)
+                    .i32_const(2)
+                    .instr(AtomicRmw {
+                        memory,
+                        op: AtomicOp::Xchg,
+                        width: AtomicWidth::I32,
+                        arg: MemArg { align: 4, offset: 0 },
+                    })
+                    .drop()
+                    .i32_const(INIT_GUARD_OFFSET)
+                    .i32_const(-1) // notify every waiter
+                    .instr(AtomicNotify {
+                        memory,
+                        arg: MemArg { align: 4, offset: 0 },
+                    })
+                    .drop();
+                winner.id()
+            },
+        });
+
+    Ok(builder.finish(vec![], &mut module.funcs))
+}
+
+/// Step 4: each thread's stack base is `stack_top - tid * stack_size`, where
+/// `stack_top` is whatever the single-threaded module already computed as
+/// its stack pointer initializer, and `tid` comes from `config.thread_id`.
+fn lower_stack_pointer(module: &mut Module, config: &ThreadConfig, stack_top: i32) -> Result<()> {
+    let stack_size = stack_size_bytes(config)?;
+
+    let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+    let mut body = builder.func_body();
+    body.i32_const(stack_top)
+        .global_get(config.thread_id)
+        .i32_const(stack_size)
+        .binop(BinaryOp::I32Mul)
+        .binop(BinaryOp::I32Sub)
+        .global_set(config.stack_pointer);
+    let reset = builder.finish(vec![], &mut module.funcs);
+    module.exports.add("__wasm_init_tls", reset);
+    Ok(())
+}